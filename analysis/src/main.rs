@@ -8,45 +8,184 @@ use std::io::Write as _;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
-fn download_crates() {
-    use crates_io_api::{CratesQuery, Sort, SyncClient};
+/// Which `crates.io` listing order to pull the corpus from.
+#[derive(Debug, Clone, Copy)]
+enum CrateSortOrder {
+    Downloads,
+    RecentDownloads,
+    RecentlyUpdated,
+}
+
+impl CrateSortOrder {
+    fn into_api_sort(self) -> crates_io_api::Sort {
+        match self {
+            CrateSortOrder::Downloads => crates_io_api::Sort::Downloads,
+            CrateSortOrder::RecentDownloads => crates_io_api::Sort::RecentDownloads,
+            CrateSortOrder::RecentlyUpdated => crates_io_api::Sort::RecentUpdates,
+        }
+    }
+}
+
+/// Parameters of a corpus of crates to fetch, e.g. "top 500 proc-macro-heavy crates".
+#[derive(Debug, Clone)]
+struct CrateSelection {
+    pages: u64,
+    max_crates: Option<u64>,
+    min_downloads: u64,
+    sort: CrateSortOrder,
+    category: Option<String>,
+    keyword: Option<String>,
+}
+
+impl Default for CrateSelection {
+    fn default() -> Self {
+        CrateSelection {
+            pages: 1,
+            max_crates: None,
+            min_downloads: 0,
+            sort: CrateSortOrder::Downloads,
+            category: None,
+            keyword: None,
+        }
+    }
+}
+
+/// Reads the `--pages`, `--max-crates`, `--min-downloads`, `--sort`, `--category` and `--keyword`
+/// flags into a [`CrateSelection`], falling back to the historical "page 1 of the top-100 by
+/// downloads" behavior for anything that isn't passed.
+fn parse_crate_selection() -> anyhow::Result<CrateSelection> {
+    let args: Vec<String> = std::env::args().collect();
+    let flag = |name: &str| -> Option<String> {
+        args.windows(2)
+            .find(|w| w[0] == name)
+            .map(|w| w[1].clone())
+    };
+
+    let mut selection = CrateSelection::default();
+    if let Some(value) = flag("--pages") {
+        selection.pages = value.parse()?;
+    }
+    if let Some(value) = flag("--max-crates") {
+        selection.max_crates = Some(value.parse()?);
+    }
+    if let Some(value) = flag("--min-downloads") {
+        selection.min_downloads = value.parse()?;
+    }
+    if let Some(value) = flag("--sort") {
+        selection.sort = match value.as_str() {
+            "downloads" => CrateSortOrder::Downloads,
+            "recent-downloads" => CrateSortOrder::RecentDownloads,
+            "recently-updated" => CrateSortOrder::RecentlyUpdated,
+            other => return Err(anyhow::anyhow!("unknown --sort order `{other}`")),
+        };
+    }
+    selection.category = flag("--category");
+    selection.keyword = flag("--keyword");
+    Ok(selection)
+}
+
+/// Strips a trailing `-<version>` suffix from a `compile-benchmarks` directory name (e.g.
+/// `serde-1.0.118` becomes `serde`), so it can be compared against a bare crate name. Directories
+/// that aren't suffixed with a version (the component after the last `-` doesn't start with a
+/// digit) are returned unchanged.
+fn benchmark_dir_crate_name(dir_name: &str) -> &str {
+    match dir_name.rsplit_once('-') {
+        Some((name, version)) if version.starts_with(|c: char| c.is_ascii_digit()) => name,
+        _ => dir_name,
+    }
+}
+
+/// Names of benchmarks already present in `compile-benchmarks`, used to dedupe a freshly fetched
+/// corpus against what we already have. Directory names are stripped of their `-<version>` suffix
+/// so e.g. `serde-1.0.118` matches a fetched crate named `serde`.
+fn existing_benchmark_names(root_dir: &Path) -> anyhow::Result<std::collections::HashSet<String>> {
+    Ok(std::fs::read_dir(root_dir.join("collector/compile-benchmarks"))?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .filter_map(|e| e.file_name().to_str().map(benchmark_dir_crate_name).map(str::to_string))
+        .collect())
+}
+
+/// Writes the exact `(name, version)` pairs that were fetched, so a corpus assembled from a live
+/// `crates.io` query stays reproducible.
+fn save_crate_manifest(crates: &[(String, String)]) -> anyhow::Result<()> {
+    let mut file = BufWriter::new(File::create("crate-manifest.csv")?);
+    writeln!(file, "name,version")?;
+    for (name, version) in crates {
+        writeln!(file, "{name},{version}")?;
+    }
+    Ok(())
+}
+
+fn download_crates(selection: &CrateSelection, root_dir: &Path) -> anyhow::Result<()> {
+    use crates_io_api::{CratesQuery, SyncClient};
     let client = SyncClient::new(
         "rustc-perf-analysis (someone@somewhere.org)",
         std::time::Duration::from_millis(1000),
-    )
-    .unwrap();
+    )?;
+
+    let existing = existing_benchmark_names(root_dir)?;
 
     let mut crates = Vec::new();
-    for page in 1..2 {
-        let mut query = CratesQuery::builder()
-            .sort(Sort::Downloads)
-            .page_size(100)
-            .build();
+    for page in 1..=selection.pages {
+        let mut builder = CratesQuery::builder()
+            .sort(selection.sort.into_api_sort())
+            .page_size(100);
+        if let Some(category) = &selection.category {
+            builder = builder.category(category.clone());
+        }
+        if let Some(keyword) = &selection.keyword {
+            builder = builder.keyword(keyword.clone());
+        }
+        let mut query = builder.build();
         query.set_page(page);
-        let response = client.crates(query).unwrap();
+        let response = client.crates(query)?;
 
         for c in response.crates {
+            if c.downloads < selection.min_downloads {
+                continue;
+            }
+            if existing.contains(&c.name) {
+                continue;
+            }
             crates.push((c.name, c.max_stable_version.unwrap_or(c.max_version)));
         }
     }
-    for krate in crates {
+    // `dedup_by` only removes *adjacent* duplicates; crates.io can return the same crate on more
+    // than one page (e.g. rankings shifting between requests), so dedupe by name across the whole
+    // corpus instead.
+    let mut seen_names = std::collections::HashSet::new();
+    crates.retain(|(name, _)| seen_names.insert(name.clone()));
+    if let Some(max_crates) = selection.max_crates {
+        crates.truncate(max_crates as usize);
+    }
+
+    save_crate_manifest(&crates)?;
+
+    for (name, version) in &crates {
         Command::new("./target/release/collector")
             .current_dir(env!("CARGO_MANIFEST_DIR"))
             .arg("download")
             .arg("crate")
-            .arg(krate.0)
-            .arg(krate.1)
-            .status()
-            .unwrap();
+            .arg(name)
+            .arg(version)
+            .status()?;
     }
+    Ok(())
 }
 
 #[derive(Default, Debug, Clone)]
 pub struct CompilationSection {
     pub name: String,
-    // It is unspecified if this is duration, fraction or something else. It should only be
-    // evaluated against the total sum of values.
+    // "Recompiled" work: self time (or span duration) with any `QueryCacheHit` events already
+    // excluded. It is unspecified if this is duration, fraction or something else, and it should
+    // only be evaluated against the total sum of values.
     pub value: u64,
+    // Number of query-cache-hit events folded into this section's labels, i.e. work that was
+    // loaded from the incremental cache rather than recomputed (always 0 for a "full",
+    // non-incremental scenario). `QueryCacheHit` events are instantaneous, so this is a count, not
+    // a duration - there's no reliable per-event replayed-time figure to report instead.
+    pub cache_hits: u64,
 }
 
 fn extract_profiling_data(path: &Path) -> anyhow::Result<ProfilingData> {
@@ -55,107 +194,534 @@ fn extract_profiling_data(path: &Path) -> anyhow::Result<ProfilingData> {
         .map_err(|_| anyhow::Error::msg("could not parse profiling data"))
 }
 
+/// One endpoint of a [`SectionKind::SpanBetween`] measurement.
+#[derive(Debug, Clone)]
+enum SpanEndpoint {
+    /// The start of the very first event in the whole profile.
+    FirstEvent,
+    /// The start of a label's last occurrence.
+    LabelStart(String),
+    /// The end of a label's last occurrence.
+    LabelEnd(String),
+}
+
+/// How a [`SectionDef`] should be turned into a [`CompilationSection`] value.
+#[derive(Debug, Clone)]
+enum SectionKind {
+    /// Sum the self time (i.e. excluding nested queries) of every event whose label is in
+    /// `labels`.
+    SelfTime,
+    /// Sum the full wall-clock duration (including any nested queries) of every event whose
+    /// label is in `labels`, unlike `SelfTime` this doesn't deduct child time.
+    WallInterval,
+    /// Measure the wall-clock interval between two anchors.
+    SpanBetween {
+        start: SpanEndpoint,
+        end: SpanEndpoint,
+    },
+}
+
+/// Declarative description of a single profiling breakdown column. Adding a new section (e.g. for
+/// `evaluate_obligation`, `LLVM_module_codegen`, `expand_crate` or `resolve_instance`) only
+/// requires adding an entry here, not touching the extraction loop below.
+#[derive(Debug, Clone)]
+struct SectionDef {
+    name: String,
+    labels: Vec<String>,
+    kind: SectionKind,
+}
+
+fn default_section_defs() -> Vec<SectionDef> {
+    vec![
+        SectionDef {
+            name: "Frontend".to_string(),
+            labels: vec![],
+            kind: SectionKind::SpanBetween {
+                start: SpanEndpoint::FirstEvent,
+                // Frontend ends where codegen *begins*, not where `codegen_crate` ends - that
+                // query spans the entire backend, so ending there would make Frontend swallow
+                // all of Backend's duration too.
+                end: SpanEndpoint::LabelStart("codegen_crate".to_string()),
+            },
+        },
+        SectionDef {
+            name: "Backend".to_string(),
+            labels: vec![],
+            kind: SectionKind::SpanBetween {
+                start: SpanEndpoint::LabelStart("codegen_crate".to_string()),
+                end: SpanEndpoint::LabelEnd("finish_ongoing_codegen".to_string()),
+            },
+        },
+        SectionDef {
+            name: "Linker".to_string(),
+            // The "link" query overlaps codegen, so we want to look at the "link_crate" query
+            // instead.
+            labels: vec!["link_crate".to_string()],
+            kind: SectionKind::SelfTime,
+        },
+        SectionDef {
+            name: "typeck".to_string(),
+            labels: vec!["type_check_crate".to_string()],
+            kind: SectionKind::SelfTime,
+        },
+        SectionDef {
+            name: "borrowck".to_string(),
+            labels: vec!["mir_borrowck".to_string()],
+            kind: SectionKind::SelfTime,
+        },
+        SectionDef {
+            name: "metadata".to_string(),
+            labels: vec!["generate_crate_metadata".to_string()],
+            kind: SectionKind::SelfTime,
+        },
+    ]
+}
+
 fn compute_compilation_sections(profile: &ProfilingData) -> Vec<CompilationSection> {
-    let mut first_event_start = None;
-    let mut backend_start = None;
-    let mut backend_end = None;
-    let mut linker_duration = None;
-    let mut mir_borrowck = 0;
-    let mut sections = vec![];
+    compute_compilation_sections_with(profile, &default_section_defs())
+}
+
+/// One recorded (non-cache-hit) query/event, reduced to just what
+/// [`compute_self_time_by_label`] needs: its label and its (start, end) interval. The thread it
+/// ran on is the key it is grouped under.
+struct IntervalEvent {
+    label: String,
+    start: std::time::SystemTime,
+    end: std::time::SystemTime,
+}
+
+/// Finishes accounting for the event at `idx`: records its self time (its own duration minus
+/// the combined duration of whatever was nested directly inside it), then folds its *total*
+/// duration into its parent's child-time tally so the parent's own self time excludes it too.
+fn finish_interval_event(
+    idx: usize,
+    child_ns: u64,
+    events: &[IntervalEvent],
+    stack: &mut [(usize, u64)],
+    self_time_by_label: &mut std::collections::HashMap<String, u64>,
+) {
+    let event = &events[idx];
+    let total_ns = event
+        .end
+        .duration_since(event.start)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let self_ns = total_ns.saturating_sub(child_ns);
+    *self_time_by_label.entry(event.label.clone()).or_insert(0) += self_ns;
+    if let Some((_, parent_child_ns)) = stack.last_mut() {
+        *parent_child_ns += total_ns;
+    }
+}
+
+/// Computes each label's total self time (elapsed time excluding any nested events) by replaying
+/// each thread's events as a call stack, since queries recorded by the self-profiler nest the way
+/// stack frames do: an event's self time is its own duration minus the combined duration of every
+/// event nested directly inside it.
+///
+/// This assumes `measureme`/`analyzeme` events on the same thread never overlap except by strict
+/// nesting (no partial overlaps), which holds for rustc's query self-profile data.
+fn compute_self_time_by_label(
+    events_by_thread: std::collections::HashMap<u32, Vec<IntervalEvent>>,
+) -> std::collections::HashMap<String, u64> {
+    let mut self_time_by_label: std::collections::HashMap<String, u64> =
+        std::collections::HashMap::new();
+
+    for (_thread_id, mut events) in events_by_thread {
+        events.sort_by_key(|event| event.start);
+        // Stack of (event index, combined duration of its direct children so far).
+        let mut stack: Vec<(usize, u64)> = Vec::new();
+        for idx in 0..events.len() {
+            while let Some(&(top_idx, child_ns)) = stack.last() {
+                if events[top_idx].end <= events[idx].start {
+                    stack.pop();
+                    finish_interval_event(top_idx, child_ns, &events, &mut stack, &mut self_time_by_label);
+                } else {
+                    break;
+                }
+            }
+            stack.push((idx, 0));
+        }
+        while let Some((top_idx, child_ns)) = stack.pop() {
+            finish_interval_event(top_idx, child_ns, &events, &mut stack, &mut self_time_by_label);
+        }
+    }
+
+    self_time_by_label
+}
+
+/// Extracts a [`CompilationSection`] for every entry of `defs` by making a single pass over
+/// `profile`. `SelfTime` sections accumulate genuine self time (see
+/// [`compute_self_time_by_label`]) across all of their labels so that nested queries aren't
+/// double-counted; `WallInterval` sections instead sum the full (non-deduplicated) interval
+/// duration of every matching event; `SpanBetween` sections resolve their two endpoints against
+/// the last start/end timestamp seen for the referenced labels (or the first event in the whole
+/// profile), matching the historical codegen span tracking.
+///
+/// For incremental scenarios, a query that's loaded from the incremental cache instead of
+/// recomputed is recorded by the self-profiler as its own `QueryCacheHit` event, alongside the
+/// query event it replaced, not as a zero-width occurrence of that query's own label. `value`
+/// already excludes that replayed work (it's only accumulated from non-cache-hit events); we
+/// additionally report how many of those cache hits landed in each section as `cache_hits`.
+/// `QueryCacheHit` events are instantaneous, so there's no reliable per-event time to attribute to
+/// them - a count is what the self-profiler actually gives us here.
+fn compute_compilation_sections_with(
+    profile: &ProfilingData,
+    defs: &[SectionDef],
+) -> Vec<CompilationSection> {
+    let mut wall_time_by_label: std::collections::HashMap<String, u64> =
+        std::collections::HashMap::new();
+    let mut cache_hits_by_label: std::collections::HashMap<String, u64> =
+        std::collections::HashMap::new();
+    let mut start_by_label = std::collections::HashMap::new();
+    let mut end_by_label = std::collections::HashMap::new();
+    let mut first_event_start: Option<std::time::SystemTime> = None;
+    let mut events_by_thread: std::collections::HashMap<u32, Vec<IntervalEvent>> =
+        std::collections::HashMap::new();
+    let mut cache_hit_starts: Vec<std::time::SystemTime> = Vec::new();
 
     for event in profile.iter_full() {
+        let Some(timestamp) = event.payload.timestamp() else {
+            continue;
+        };
+        let (start, end) = (timestamp.start(), timestamp.end());
         if first_event_start.is_none() {
-            first_event_start = event.payload.timestamp().map(|t| t.start());
+            first_event_start = Some(start);
         }
-        if event.label == "type_check_crate" {
-            sections.push(CompilationSection {
-                name: "typeck".to_string(),
-                value: event
-                    .payload
-                    .timestamp()
-                    .map(|t| t.end().duration_since(t.start()))
-                    .unwrap()
-                    .unwrap()
-                    .as_nanos() as u64,
-            });
-        } else if event.label == "mir_borrowck" {
-            mir_borrowck += event
-                .payload
-                .timestamp()
-                .map(|t| t.end().duration_since(t.start()))
-                .unwrap()
-                .unwrap()
-                .as_nanos() as u64;
-        } else if event.label == "generate_crate_metadata" {
-            sections.push(CompilationSection {
-                name: "metadata".to_string(),
-                value: event
-                    .payload
-                    .timestamp()
-                    .map(|t| t.end().duration_since(t.start()))
-                    .unwrap()
-                    .unwrap()
-                    .as_nanos() as u64,
-            });
-        } else if event.label == "codegen_crate" {
-            // Start of "codegen_crate" => start of backend
-            backend_start = event.payload.timestamp().map(|t| t.start());
-        } else if event.label == "finish_ongoing_codegen" {
-            // End of "finish_ongoing_codegen" => end of backend
-            backend_end = event.payload.timestamp().map(|t| t.end());
-        } else if event.label == "link_crate" {
-            // The "link" query overlaps codegen, so we want to look at the "link_crate" query
-            // instead.
-            linker_duration = event.duration();
+
+        // rustc's self-profiler records a cache hit as its own `QueryCacheHit` event kind, not
+        // as a zero-width occurrence of the query it replaced.
+        let is_cache_hit = event.event_kind.as_ref() == "QueryCacheHit";
+        if is_cache_hit {
+            *cache_hits_by_label.entry(event.label.to_string()).or_insert(0) += 1;
+            cache_hit_starts.push(start);
+            // A cache hit is an instantaneous marker, not the query it replaced - don't let it
+            // stamp a SpanBetween endpoint with a bogus zero-width timestamp.
+            continue;
         }
-    }
-    if let (Some(start), Some(end)) = (first_event_start, backend_start) {
-        if let Ok(duration) = end.duration_since(start) {
-            sections.push(CompilationSection {
-                name: "Frontend".to_string(),
-                value: duration.as_nanos() as u64,
+
+        let duration_ns = end.duration_since(start).unwrap_or_default().as_nanos() as u64;
+        *wall_time_by_label.entry(event.label.to_string()).or_insert(0) += duration_ns;
+        start_by_label.insert(event.label.to_string(), start);
+        end_by_label.insert(event.label.to_string(), end);
+
+        events_by_thread
+            .entry(event.thread_id)
+            .or_default()
+            .push(IntervalEvent {
+                label: event.label.to_string(),
+                start,
+                end,
             });
-        }
     }
-    if let (Some(start), Some(end)) = (backend_start, backend_end) {
-        if let Ok(duration) = end.duration_since(start) {
-            sections.push(CompilationSection {
-                name: "Backend".to_string(),
-                value: duration.as_nanos() as u64,
-            });
+
+    let self_time_by_label = compute_self_time_by_label(events_by_thread);
+
+    let resolve_endpoint = |endpoint: &SpanEndpoint| -> Option<std::time::SystemTime> {
+        match endpoint {
+            SpanEndpoint::FirstEvent => first_event_start,
+            SpanEndpoint::LabelStart(label) => start_by_label.get(label).copied(),
+            SpanEndpoint::LabelEnd(label) => end_by_label.get(label).copied(),
         }
-    }
-    if let Some(duration) = linker_duration {
+    };
+
+    let mut sections = vec![];
+    for def in defs {
+        let value = match &def.kind {
+            SectionKind::SelfTime => def
+                .labels
+                .iter()
+                .filter_map(|label| self_time_by_label.get(label))
+                .sum(),
+            SectionKind::WallInterval => def
+                .labels
+                .iter()
+                .filter_map(|label| wall_time_by_label.get(label))
+                .sum(),
+            SectionKind::SpanBetween { start, end } => {
+                match (resolve_endpoint(start), resolve_endpoint(end)) {
+                    (Some(start), Some(end)) => match end.duration_since(start) {
+                        Ok(duration) => duration.as_nanos() as u64,
+                        Err(_) => 0,
+                    },
+                    // Still emit the section (as 0) so every result has one entry per def and
+                    // the CSV columns stay aligned with the header.
+                    _ => 0,
+                }
+            }
+        };
+        let cache_hits = match &def.kind {
+            SectionKind::SpanBetween { start, end } => {
+                // A span has no labels of its own, so count whichever cache hits landed inside
+                // its [start, end) window.
+                match (resolve_endpoint(start), resolve_endpoint(end)) {
+                    (Some(start), Some(end)) => cache_hit_starts
+                        .iter()
+                        .filter(|hit_start| **hit_start >= start && **hit_start < end)
+                        .count() as u64,
+                    _ => 0,
+                }
+            }
+            SectionKind::SelfTime | SectionKind::WallInterval => def
+                .labels
+                .iter()
+                .filter_map(|label| cache_hits_by_label.get(label))
+                .sum(),
+        };
         sections.push(CompilationSection {
-            name: "Linker".to_string(),
-            value: duration.as_nanos() as u64,
+            name: def.name.clone(),
+            value,
+            cache_hits,
         });
     }
-    sections.push(CompilationSection {
-        name: "borrowck".to_string(),
-        value: mir_borrowck,
-    });
 
     sections
 }
 
+/// The codegen backend that a profiled rustc invocation used. Mainly affects the Backend and
+/// Linker sections, since the frontend (parsing, type checking, borrowck) is shared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CodegenBackend {
+    Llvm,
+    Cranelift,
+}
+
+impl CodegenBackend {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CodegenBackend::Llvm => "llvm",
+            CodegenBackend::Cranelift => "cranelift",
+        }
+    }
+
+    /// Path to the `rustc_codegen_cranelift` shared library shipped by the nightly
+    /// `rustc-codegen-cranelift-preview` sysroot component, if it is installed.
+    ///
+    /// The component installs into `<sysroot>/lib/rustlib/<host>/codegen-backends/`, a sibling of
+    /// (not inside) `--print target-libdir`, so the libdir has to be derived from the sysroot
+    /// rather than printed directly.
+    fn cranelift_backend_path() -> Option<PathBuf> {
+        let output = Command::new("rustc")
+            .arg("+nightly")
+            .arg("--print")
+            .arg("sysroot")
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let sysroot = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        let candidate = PathBuf::from(sysroot)
+            .join("lib/rustlib")
+            .join(host_target().ok()?)
+            .join("codegen-backends")
+            .join(format!(
+                "{}rustc_codegen_cranelift{}",
+                std::env::consts::DLL_PREFIX,
+                std::env::consts::DLL_SUFFIX
+            ));
+        candidate.is_file().then_some(candidate)
+    }
+
+    /// Returns `false` when this backend's toolchain isn't installed, so the caller can skip it.
+    fn is_available(&self) -> bool {
+        match self {
+            CodegenBackend::Llvm => true,
+            CodegenBackend::Cranelift => Self::cranelift_backend_path().is_some(),
+        }
+    }
+}
+
+impl std::str::FromStr for CodegenBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "llvm" => Ok(CodegenBackend::Llvm),
+            "cranelift" => Ok(CodegenBackend::Cranelift),
+            _ => Err(anyhow::anyhow!("unknown codegen backend `{s}`")),
+        }
+    }
+}
+
+/// Reads `--codegen-backends <comma-separated list>` from the process arguments, defaulting to
+/// just `llvm` when the flag isn't passed.
+fn parse_codegen_backends() -> anyhow::Result<Vec<CodegenBackend>> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.windows(2).find(|w| w[0] == "--codegen-backends") {
+        Some(w) => w[1].split(',').map(|s| s.parse()).collect(),
+        None => Ok(vec![CodegenBackend::Llvm]),
+    }
+}
+
+/// The host's target triple, used as the default of `--targets` when it isn't passed.
+fn host_target() -> anyhow::Result<String> {
+    let arch = match std::env::consts::ARCH {
+        "x86_64" => "x86_64",
+        "aarch64" => "aarch64",
+        "arm" => "arm",
+        other => return Err(anyhow::anyhow!("unsupported host architecture `{other}`")),
+    };
+    let triple = match std::env::consts::OS {
+        "linux" => format!("{arch}-unknown-linux-gnu"),
+        // Apple Silicon hosts are aarch64-apple-darwin, not x86_64-apple-darwin - the old
+        // cfg(target_os = "macos") arm always returned the Intel triple regardless of arch.
+        "macos" => format!("{arch}-apple-darwin"),
+        other => return Err(anyhow::anyhow!("unsupported host OS `{other}`")),
+    };
+    Ok(triple)
+}
+
+/// Reads `--targets <comma-separated list of target triples>` from the process arguments,
+/// defaulting to just the host triple when the flag isn't passed.
+fn parse_targets() -> anyhow::Result<Vec<String>> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.windows(2).find(|w| w[0] == "--targets") {
+        Some(w) => Ok(w[1].split(',').map(|s| s.to_string()).collect()),
+        None => Ok(vec![host_target()?]),
+    }
+}
+
+/// Maps a target triple to the `rustc` binary of its nightly rustup toolchain, erroring out if
+/// that toolchain isn't installed.
+fn rustc_path_for_target(target: &str) -> anyhow::Result<PathBuf> {
+    let path = PathBuf::from(format!(
+        "{}/.rustup/toolchains/nightly-{target}/bin/rustc",
+        env!("HOME")
+    ));
+    if !path.is_file() {
+        return Err(anyhow::anyhow!(
+            "no nightly toolchain for target `{target}` found at {}",
+            path.display()
+        ));
+    }
+    Ok(path)
+}
+
+const CPU_BOOST_PATH: &str = "/sys/devices/system/cpu/cpufreq/boost";
+
+/// Opt-in config for [`stabilize_environment`], parsed from `--stabilize [<cores>]`.
+#[derive(Debug, Clone, Default)]
+struct StabilizeConfig {
+    /// Core list to pass to `taskset -c` when profiling, e.g. `"0-3"`.
+    pin_cores: Option<String>,
+}
+
+/// Reads `--stabilize` (optionally followed by a core list, e.g. `--stabilize 0-3`) from the
+/// process arguments. Returns `None` when the flag isn't passed, since stabilization is opt-in.
+fn parse_stabilize_config() -> Option<StabilizeConfig> {
+    let args: Vec<String> = std::env::args().collect();
+    let index = args.iter().position(|a| a == "--stabilize")?;
+    let pin_cores = args
+        .get(index + 1)
+        .filter(|a| !a.starts_with("--"))
+        .cloned();
+    Some(StabilizeConfig { pin_cores })
+}
+
+/// Restores the CPU knobs that [`stabilize_environment`] changed once it goes out of scope.
+struct StabilizationGuard {
+    previous_boost: Option<String>,
+    previous_governors: Vec<(PathBuf, String)>,
+    /// Human-readable description of each knob that was actually applied, e.g. `"boost=disabled"`.
+    applied: Vec<String>,
+}
+
+impl Drop for StabilizationGuard {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous_boost.take() {
+            let _ = std::fs::write(CPU_BOOST_PATH, previous);
+        }
+        for (path, governor) in self.previous_governors.drain(..) {
+            let _ = std::fs::write(path, governor);
+        }
+    }
+}
+
+fn cpu_scaling_governor_paths() -> Vec<PathBuf> {
+    std::fs::read_dir("/sys/devices/system/cpu")
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("cpu") && n[3..].chars().all(|c| c.is_ascii_digit()))
+        })
+        .map(|p| p.join("cpufreq/scaling_governor"))
+        .filter(|p| p.is_file())
+        .collect()
+}
+
+/// Reduces measurement noise before profiling: disables CPU frequency boost and forces the
+/// `performance` governor on every core, restoring both when the returned guard is dropped. Core
+/// pinning (`config.pin_cores`) is applied separately via `taskset` when spawning the collector,
+/// since that only needs to affect the child process. Every knob is best-effort: a sysfs write
+/// that fails (e.g. no root, or running in a container) is silently skipped and not recorded as
+/// applied, since the Frontend/Backend nanosecond values are only as trustworthy as the knobs that
+/// actually took effect.
+fn stabilize_environment(config: &StabilizeConfig) -> StabilizationGuard {
+    let mut guard = StabilizationGuard {
+        previous_boost: None,
+        previous_governors: vec![],
+        applied: vec![],
+    };
+
+    if let Ok(previous) = std::fs::read_to_string(CPU_BOOST_PATH) {
+        if std::fs::write(CPU_BOOST_PATH, "0").is_ok() {
+            guard.previous_boost = Some(previous);
+            guard.applied.push("boost=disabled".to_string());
+        }
+    }
+
+    for path in cpu_scaling_governor_paths() {
+        if let Ok(previous) = std::fs::read_to_string(&path) {
+            if std::fs::write(&path, "performance").is_ok() {
+                guard.previous_governors.push((path, previous));
+            }
+        }
+    }
+    if !guard.previous_governors.is_empty() {
+        guard.applied.push("governor=performance".to_string());
+    }
+
+    if let Some(cores) = &config.pin_cores {
+        guard.applied.push(format!("pinned_cores={cores}"));
+    }
+
+    guard
+}
+
+/// Writes which stabilization knobs were actually applied to a sidecar file next to
+/// `results.csv`, so a reader of the CSV can tell how trustworthy the noise floor is.
+fn save_stabilization_metadata(guard: &StabilizationGuard) -> anyhow::Result<()> {
+    let mut file = BufWriter::new(File::create("results.stabilize.txt")?);
+    for knob in &guard.applied {
+        writeln!(file, "{knob}")?;
+    }
+    Ok(())
+}
+
+/// A single profiled run. `sections` is whatever [`default_section_defs`] produced, kept as a
+/// list instead of named fields so adding a `SectionDef` automatically reaches `save_results`'s
+/// CSV without touching this struct.
 struct BenchResult {
     benchmark: String,
     kind: String,
     profile: String,
     scenario: String,
-    frontend: u64,
-    backend: u64,
-    linker: u64,
-    typeck: u64,
-    borrowck: u64,
-    metadata: u64,
+    target: String,
+    backend_impl: String,
+    sections: Vec<CompilationSection>,
 }
 
 fn run_benchmark(
     benchmark: &Path,
     root_dir: &Path,
     result_dir: &Path,
+    target_triple: &str,
+    codegen_backend: CodegenBackend,
+    pin_cores: Option<&str>,
     results: &mut Vec<BenchResult>,
 ) -> anyhow::Result<()> {
     let name = benchmark.file_name().unwrap().to_str().unwrap();
@@ -206,28 +772,33 @@ fn run_benchmark(
     let diff = diff.replace(patched_path.to_str().unwrap(), &format!("/{relative_path}"));
     std::fs::write(&patch_file, &diff)?;
 
-    // TODO see if can get the right triple as an env var directly...
-    #[cfg(target_arch = "x86_64")]
-    let arch = "x86_64";
-    #[cfg(target_arch = "arm")]
-    let arch = "arm";
-    #[cfg(target_arch = "aarch64")]
-    let arch = "aarch64";
-
-    #[cfg(target_os = "linux")]
-    let os = "unknown-linux-gnu";
-    #[cfg(target_os = "macos")]
-    let os = "apple-darwin";
-
-    let rustc_path = format!("{}/.rustup/toolchains/nightly-{arch}-{os}/bin/rustc", env!("HOME"));
-    let status = Command::new("./target/release/collector")
+    let rustc_path = rustc_path_for_target(target_triple)?;
+    let mut command = match pin_cores {
+        Some(cores) => {
+            let mut c = Command::new("taskset");
+            c.arg("-c").arg(cores).arg("./target/release/collector");
+            c
+        }
+        None => Command::new("./target/release/collector"),
+    };
+    command
         .current_dir(root_dir)
         .arg("profile_local")
         .arg("self-profile")
         .arg(rustc_path)
         .arg("--include")
         .arg(name)
-        .status()?;
+        .arg("--target")
+        .arg(target_triple);
+    if codegen_backend == CodegenBackend::Cranelift {
+        let backend_path = CodegenBackend::cranelift_backend_path()
+            .ok_or_else(|| anyhow::anyhow!("cranelift codegen backend is not installed"))?;
+        command.env(
+            "RUSTFLAGS",
+            format!("-Zcodegen-backend={}", backend_path.display()),
+        );
+    }
+    let status = command.status()?;
     if !status.success() {
         return Err(anyhow::anyhow!(
             "Failed to benchmark {name}: {}",
@@ -255,9 +826,18 @@ fn run_benchmark(
         let scenario = dir_parts.next().unwrap();
         let profile = dir_parts.next().unwrap();
 
-        let sections = compute_compilation_sections(&data);
+        let mut sections = compute_compilation_sections(&data);
         // println!("{name} ({profile}/{scenario}): {sections:?}");
 
+        // Cache hits only mean anything for incremental scenarios; a "full" build never consults
+        // the incremental cache, so zero them out defensively even if the profile disagrees.
+        let is_incremental = scenario.starts_with("incr");
+        if !is_incremental {
+            for section in &mut sections {
+                section.cache_hits = 0;
+            }
+        }
+
         let result = BenchResult {
             benchmark: name.to_string(),
             kind: if is_binary {
@@ -267,70 +847,59 @@ fn run_benchmark(
             },
             profile,
             scenario,
-            frontend: sections
-                .iter()
-                .find(|s| s.name == "Frontend")
-                .map(|s| s.value)
-                .ok_or_else(|| anyhow::anyhow!("Could not find frontend"))?,
-            backend: sections
-                .iter()
-                .find(|s| s.name == "Backend")
-                .map(|s| s.value)
-                .ok_or_else(|| anyhow::anyhow!("Could not find backend"))?,
-            linker: sections
-                .iter()
-                .find(|s| s.name == "Linker")
-                .map(|s| s.value)
-                .ok_or_else(|| anyhow::anyhow!("Could not find linker"))?,
-            typeck: sections
-                .iter()
-                .find(|s| s.name == "typeck")
-                .map(|s| s.value)
-                .unwrap_or(0),
-            borrowck: sections
-                .iter()
-                .find(|s| s.name == "borrowck")
-                .map(|s| s.value)
-                .unwrap_or(0),
-            metadata: sections
-                .iter()
-                .find(|s| s.name == "metadata")
-                .map(|s| s.value)
-                .unwrap_or(0),
+            target: target_triple.to_string(),
+            backend_impl: codegen_backend.as_str().to_string(),
+            sections,
         };
         results.push(result);
     }
     Ok(())
 }
 
+/// Writes `results.csv` with one `<section>,<section>_cached` pair of columns per entry of
+/// `default_section_defs`, so adding a `SectionDef` (e.g. for `evaluate_obligation` or
+/// `LLVM_module_codegen`) shows up here without editing this function.
 fn save_results(results: &[BenchResult]) -> anyhow::Result<()> {
     let mut file = BufWriter::new(File::create("results.csv")?);
-    writeln!(
-        file,
-        "benchmark,kind,profile,scenario,frontend,backend,linker,borrowck,typeck,metadata"
-    )?;
+
+    let mut header = "benchmark,kind,profile,scenario,target,backend_impl".to_string();
+    if let Some(first) = results.first() {
+        for section in &first.sections {
+            write!(header, ",{0},{0}_cached", section.name.to_lowercase())?;
+        }
+    }
+    writeln!(file, "{header}")?;
+
     for result in results {
-        let BenchResult {
-            benchmark,
-            kind,
-            profile,
-            scenario,
-            frontend,
-            backend,
-            linker,
-            typeck,
-            borrowck,
-            metadata,
-        } = result;
-        writeln!(
-            file,
-            "{benchmark},{kind},{profile},{scenario},{frontend},{backend},{linker},{borrowck},{typeck},{metadata}"
-        )?;
+        let mut row = format!(
+            "{},{},{},{},{},{}",
+            result.benchmark,
+            result.kind,
+            result.profile,
+            result.scenario,
+            result.target,
+            result.backend_impl
+        );
+        for section in &result.sections {
+            write!(row, ",{},{}", section.value, section.cache_hits)?;
+        }
+        writeln!(file, "{row}")?;
     }
     Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
+    // Not part of the workspace so "CARGO_MANIFEST_DIR" resolves to /analysis
+    let analysis_root_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let root_dir = analysis_root_dir.parent()
+        .ok_or(io::Error::new(io::ErrorKind::NotFound,
+                              "Could not get 'root_dir' of 'rustc-perf'"))?;
+
+    if std::env::args().any(|a| a == "--download-crates") {
+        let selection = parse_crate_selection()?;
+        return download_crates(&selection, root_dir);
+    }
+
     let mut benchmarks: Vec<_> = std::fs::read_dir("../collector/compile-benchmarks")?
         .filter_map(|e| e.ok())
         .map(|e| e.path())
@@ -345,30 +914,60 @@ fn main() -> anyhow::Result<()> {
         //     || name.contains("eza")
     });
 
-    // Not part of the workspace so "CARGO_MANIFEST_DIR" resolves to /analysis
-    let analysis_root_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    let root_dir = analysis_root_dir.parent()
-        .ok_or(io::Error::new(io::ErrorKind::NotFound,
-                              "Could not get 'root_dir' of 'rustc-perf'"))?;
     let result_dir = root_dir.join("results");
+    let codegen_backends = parse_codegen_backends()?;
+    let targets = parse_targets()?;
+    let stabilize_config = parse_stabilize_config();
+    let _stabilization_guard = stabilize_config.as_ref().map(|config| {
+        let guard = stabilize_environment(config);
+        if let Err(error) = save_stabilization_metadata(&guard) {
+            println!("Could not write stabilization metadata: {error}");
+        }
+        guard
+    });
+    let pin_cores = stabilize_config.as_ref().and_then(|c| c.pin_cores.as_deref());
 
     let mut results: Vec<BenchResult> = vec![];
     for benchmark in benchmarks.iter().progress() {
-        if result_dir.is_dir() {
-            std::fs::remove_dir_all(&result_dir)?;
-        }
-        if let Err(error) = run_benchmark(&benchmark, &root_dir, &result_dir, &mut results) {
-            println!("{} has failed: {error:?}", benchmark.display());
-        }
-        // Delete temporary files to clear disk space
-        for dir in std::fs::read_dir("/tmp")? {
-            let dir = dir?;
-            let name = dir.file_name().to_str().unwrap().to_string();
-            if name.starts_with("tmp") || name.starts_with(".tmp") {
-                let _ = std::fs::remove_dir_all(dir.path());
+        for target in &targets {
+            if let Err(error) = rustc_path_for_target(target) {
+                println!("Skipping target {target} for {}: {error}", benchmark.display());
+                continue;
+            }
+            for codegen_backend in &codegen_backends {
+                if !codegen_backend.is_available() {
+                    println!(
+                        "Skipping {} backend for {}: toolchain not installed",
+                        codegen_backend.as_str(),
+                        benchmark.display()
+                    );
+                    continue;
+                }
+                if result_dir.is_dir() {
+                    std::fs::remove_dir_all(&result_dir)?;
+                }
+                if let Err(error) = run_benchmark(
+                    &benchmark,
+                    &root_dir,
+                    &result_dir,
+                    target,
+                    *codegen_backend,
+                    pin_cores,
+                    &mut results,
+                ) {
+                    println!("{} has failed: {error:?}", benchmark.display());
+                }
+                // Delete temporary files to clear disk space
+                for dir in std::fs::read_dir("/tmp")? {
+                    let dir = dir?;
+                    let name = dir.file_name().to_str().unwrap().to_string();
+                    if name.starts_with("tmp") || name.starts_with(".tmp") {
+                        let _ = std::fs::remove_dir_all(dir.path());
+                    }
+                }
+                save_results(&results)?;
             }
         }
-        save_results(&results)?;
     }
     save_results(&results)?;
 